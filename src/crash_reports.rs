@@ -0,0 +1,81 @@
+//! Optional crash reporting to Sentry (or a Sentry compatible service like GlitchTip).
+//!
+//! Reporting is active only when the binary is built with the `sentry` feature and the
+//! `SENTRY_DSN` environment variable is set, otherwise every function here is a no-op.
+
+use crate::pb::sf::substreams::v1::Clock;
+
+#[cfg(feature = "sentry")]
+pub type Guard = sentry::ClientInitGuard;
+
+#[cfg(not(feature = "sentry"))]
+pub type Guard = ();
+
+/// Installs the panic hook and the client, events are flushed when the returned guard is dropped.
+#[cfg(feature = "sentry")]
+pub fn init() -> Option<Guard> {
+    let dsn = std::env::var("SENTRY_DSN")
+        .ok()
+        .filter(|dsn| !dsn.is_empty())?;
+
+    Some(sentry::init((
+        dsn,
+        sentry::ClientOptions {
+            release: sentry::release_name!(),
+            ..Default::default()
+        },
+    )))
+}
+
+#[cfg(not(feature = "sentry"))]
+pub fn init() -> Option<Guard> {
+    None
+}
+
+/// Forwards `error!` events to Sentry, lower levels are attached to them as breadcrumbs.
+/// Events carrying a `fatal` field are skipped, `report_fatal` already sends that error.
+#[cfg(feature = "sentry")]
+pub fn tracing_layer<S>() -> sentry::integrations::tracing::SentryLayer<S>
+where
+    S: tracing::Subscriber + for<'a> tracing_subscriber::registry::LookupSpan<'a>,
+{
+    use sentry::integrations::tracing::{default_event_filter, EventFilter};
+
+    sentry::integrations::tracing::layer().event_filter(|metadata| {
+        if metadata.fields().field("fatal").is_some() {
+            return EventFilter::Ignore;
+        }
+
+        default_event_filter(metadata)
+    })
+}
+
+#[cfg(not(feature = "sentry"))]
+pub fn tracing_layer() -> tracing_subscriber::layer::Identity {
+    tracing_subscriber::layer::Identity::new()
+}
+
+/// Attaches the block currently being processed to any event reported from now on.
+#[cfg(feature = "sentry")]
+pub fn set_block(clock: &Clock) {
+    sentry::configure_scope(|scope| {
+        scope.set_tag("block_number", clock.number);
+        scope.set_tag("block_id", &clock.id);
+    });
+}
+
+#[cfg(not(feature = "sentry"))]
+pub fn set_block(_clock: &Clock) {}
+
+/// Reports an error that is about to terminate the process, waiting for it to be sent.
+#[cfg(feature = "sentry")]
+pub fn report_fatal(err: &anyhow::Error) {
+    sentry::capture_error(AsRef::<dyn std::error::Error>::as_ref(err));
+
+    if let Some(client) = sentry::Hub::current().client() {
+        client.flush(Some(std::time::Duration::from_secs(2)));
+    }
+}
+
+#[cfg(not(feature = "sentry"))]
+pub fn report_fatal(_err: &anyhow::Error) {}
@@ -1,41 +1,108 @@
-use anyhow::{format_err, Context, Error};
+use anyhow::{bail, format_err, Context, Error};
 use futures03::StreamExt;
 use pb::sf::substreams::rpc::v2::{BlockScopedData, BlockUndoSignal};
 use pb::sf::substreams::v1::Package;
 
+use progress::SyncProgress;
 use prost::Message;
-use std::{env, process::exit, sync::Arc};
+use std::{
+    env,
+    process::exit,
+    sync::Arc,
+    time::{Duration, Instant},
+};
 use substreams::SubstreamsEndpoint;
-use substreams_stream::{BlockResponse, SubstreamsStream};
+use substreams_stream::{BlockResponse, RetriesExhausted, RetryPolicy, SubstreamsStream};
+use tracing::{debug, error, info, info_span};
+use tracing_subscriber::{fmt, prelude::*, EnvFilter};
 
+mod crash_reports;
+#[allow(dead_code)]
 mod pb;
+mod preflight;
+mod progress;
 mod substreams;
 mod substreams_stream;
 
+/// Fully qualified Protobuf type of the output module this sink decodes, checked against the
+/// module declaration at startup and against every received payload. Change it to the type
+/// your own `process_block_scoped_data` decodes.
+const EXPECTED_OUTPUT_TYPE: &str = "sf.substreams.sink.database.v1.DatabaseChanges";
+
+/// Exit code when the stream failed with an unrecoverable error.
+const EXIT_ERROR: i32 = 1;
+/// Exit code when reconnection retries were exhausted before reaching the stop block or head.
+const EXIT_LAGGING: i32 = 2;
+
 #[tokio::main]
-async fn main() -> Result<(), Error> {
+async fn main() {
+    let _crash_reports = crash_reports::init();
+
     let args = env::args();
     if args.len() < 4 || args.len() > 5 {
         println!("usage: stream <endpoint> <spkg> <module> [<start>:<stop>]");
         println!();
         println!("The environment variable SUBSTREAMS_API_TOKEN must be set also");
         println!("and should contain a valid Substream API token.");
-        exit(1);
+        println!();
+        println!("Reconnection can be tuned with SUBSTREAMS_RETRY_MAX_ATTEMPTS,");
+        println!("SUBSTREAMS_RETRY_BASE_DELAY_MS, SUBSTREAMS_RETRY_MAX_DELAY_MS and");
+        println!("SUBSTREAMS_RETRY_JITTER (true/false).");
+        println!();
+        println!(
+            "Across empty blocks, the cursor is persisted every SUBSTREAMS_CURSOR_FLUSH_BLOCKS"
+        );
+        println!("blocks (default 100) or SUBSTREAMS_CURSOR_FLUSH_INTERVAL_MS (default 10000).");
+        println!();
+        println!("Logs are filtered with RUST_LOG (default info) and formatted according to");
+        println!("SUBSTREAMS_LOG_FORMAT (pretty or json, default pretty).");
+        println!();
+        println!("Set SUBSTREAMS_EXIT_AFTER_CATCHUP=true to exit once the chain head is reached.");
+        println!("Exit code is 0 on completion, 1 on error and 2 when retries are exhausted.");
+        exit(EXIT_ERROR);
+    }
+
+    if let Err(err) = init_logging() {
+        eprintln!("{:#}", err);
+        exit(EXIT_ERROR);
+    }
+
+    if let Err(err) = run().await {
+        error!(fatal = true, "Sink terminated with error: {:?}", err);
+        crash_reports::report_fatal(&err);
+
+        if err.downcast_ref::<RetriesExhausted>().is_some() {
+            exit(EXIT_LAGGING);
+        }
+        exit(EXIT_ERROR);
     }
+}
 
+async fn run() -> Result<(), Error> {
     let endpoint_url = env::args().nth(1).unwrap();
     let package_file = env::args().nth(2).unwrap();
     let module_name = env::args().nth(3).unwrap();
 
     let token_env = env::var("SUBSTREAMS_API_TOKEN").unwrap_or("".to_string());
     let mut token: Option<String> = None;
-    if token_env.len() > 0 {
+    if !token_env.is_empty() {
         token = Some(token_env);
     }
 
-    let package = read_package(&package_file).await?;
+    let package = preflight::run(
+        &endpoint_url,
+        &package_file,
+        &module_name,
+        EXPECTED_OUTPUT_TYPE,
+        token.as_deref(),
+    )
+    .await?;
     let block_range = read_block_range(&package, &module_name)?;
     let endpoint = Arc::new(SubstreamsEndpoint::new(&endpoint_url, token).await?);
+    let retry_policy = read_retry_policy()?;
+    let mut cursor_flush = read_cursor_flush_policy()?;
+    let mut progress = SyncProgress::new(block_range.1, Duration::from_secs(30));
+    let exit_after_catchup = read_exit_after_catchup()?;
 
     let cursor: Option<String> = load_persisted_cursor()?;
 
@@ -46,38 +113,87 @@ async fn main() -> Result<(), Error> {
         module_name.to_string(),
         block_range.0,
         block_range.1,
+        retry_policy,
     );
 
     loop {
         match stream.next().await {
             None => {
-                println!("Stream consumed");
+                if let Some(cursor) = cursor_flush.take_pending() {
+                    persist_cursor(cursor)?;
+                }
+
+                info!("Stream consumed");
                 break;
             }
             Some(Ok(BlockResponse::New(data))) => {
-                process_block_scoped_data(&data)?;
-                persist_cursor(data.cursor)?;
+                let block_num = data.clock.as_ref().map(|clock| clock.number);
+                let _span = info_span!("block", number = block_num).entered();
+
+                if let Some(clock) = data.clock.as_ref() {
+                    crash_reports::set_block(clock);
+
+                    if let Some(progress) = progress.on_block(clock) {
+                        info!(
+                            block = progress.block,
+                            blocks_per_sec = progress.blocks_per_sec,
+                            eta = %progress.eta,
+                            "Progress"
+                        );
+                    }
+                }
+
+                let has_data = process_block_scoped_data(&data, &module_name)?;
+                if let Some(cursor) = cursor_flush.on_block(data.cursor, has_data) {
+                    persist_cursor(cursor)?;
+                }
+
+                if exit_after_catchup
+                    && matches!(&data.clock, Some(clock) if progress::is_live(clock))
+                {
+                    if let Some(cursor) = cursor_flush.take_pending() {
+                        persist_cursor(cursor)?;
+                    }
+
+                    info!("Caught up with chain head, exiting");
+                    break;
+                }
             }
             Some(Ok(BlockResponse::Undo(undo_signal))) => {
+                let last_valid_block = undo_signal.last_valid_block.as_ref().map(|b| b.number);
+                let _span = info_span!("undo", last_valid_block).entered();
+
                 process_block_undo_signal(&undo_signal)?;
+                cursor_flush.take_pending();
                 persist_cursor(undo_signal.last_valid_cursor)?;
             }
-            Some(Err(err)) => {
-                println!();
-                println!("Stream terminated with error");
-                println!("{:?}", err);
-                exit(1);
-            }
+            Some(Err(err)) => return Err(err.context("stream terminated")),
         }
     }
 
     Ok(())
 }
 
-fn process_block_scoped_data(data: &BlockScopedData) -> Result<(), Error> {
-    let output = data.output.as_ref().unwrap().map_output.as_ref().unwrap();
+/// Returns whether the block carried any data for the output module.
+fn process_block_scoped_data(data: &BlockScopedData, module_name: &str) -> Result<bool, Error> {
+    let output = data
+        .output
+        .as_ref()
+        .and_then(|output| output.map_output.as_ref())
+        .ok_or_else(|| format_err!("block has no map output for module '{}'", module_name))?;
 
-    // You can decode the actual Any type received using this code:
+    let output_type = output.type_url.trim_start_matches("type.googleapis.com/");
+    if output_type != EXPECTED_OUTPUT_TYPE {
+        bail!(
+            "module '{}' output '{}' but this sink decodes '{}', update EXPECTED_OUTPUT_TYPE",
+            module_name,
+            output_type,
+            EXPECTED_OUTPUT_TYPE
+        );
+    }
+
+    // You can decode the actual Any type received (declared in `EXPECTED_OUTPUT_TYPE`) using
+    // this code:
     //
     //     let value = GeneratedStructName::decode(output.value.as_slice())?;
     //
@@ -85,14 +201,13 @@ fn process_block_scoped_data(data: &BlockScopedData) -> Result<(), Error> {
     // your type, so you will need generate it using `substreams protogen` and import it from the
     // `src/pb` folder.
 
-    println!(
-        "Block #{} - Payload {} ({} bytes)",
-        data.clock.as_ref().unwrap().number,
-        output.type_url.replace("type.googleapis.com/", ""),
-        output.value.len()
+    debug!(
+        payload = output_type,
+        bytes = output.value.len(),
+        "Block processed"
     );
 
-    Ok(())
+    Ok(!output.value.is_empty())
 }
 
 fn process_block_undo_signal(_undo_signal: &BlockUndoSignal) -> Result<(), anyhow::Error> {
@@ -117,6 +232,55 @@ fn persist_cursor(_cursor: String) -> Result<(), anyhow::Error> {
     Ok(())
 }
 
+/// Decides when the cursor of a processed block must be persisted. It is written right away
+/// after a block carrying data, but runs of empty blocks only write it every `every_blocks`
+/// blocks or `every` elapsed time, saving a round trip per block during sparse backfills.
+///
+/// Skipping the write for an empty block is safe: on restart we simply resume a few empty
+/// blocks earlier.
+struct CursorFlushPolicy {
+    /// Set to 0 or 1 to persist the cursor after every block.
+    every_blocks: u64,
+    every: Duration,
+    pending: Option<String>,
+    pending_blocks: u64,
+    last_flush: Instant,
+}
+
+impl CursorFlushPolicy {
+    fn new(every_blocks: u64, every: Duration) -> Self {
+        CursorFlushPolicy {
+            every_blocks,
+            every,
+            pending: None,
+            pending_blocks: 0,
+            last_flush: Instant::now(),
+        }
+    }
+
+    /// Returns the cursor to persist now, if any.
+    fn on_block(&mut self, cursor: String, has_data: bool) -> Option<String> {
+        self.pending = Some(cursor);
+        self.pending_blocks += 1;
+
+        if has_data
+            || self.pending_blocks >= self.every_blocks
+            || self.last_flush.elapsed() >= self.every
+        {
+            return self.take_pending();
+        }
+
+        None
+    }
+
+    /// Returns the cursor not yet persisted, if any, and resets the batch.
+    fn take_pending(&mut self) -> Option<String> {
+        self.pending_blocks = 0;
+        self.last_flush = Instant::now();
+        self.pending.take()
+    }
+}
+
 fn load_persisted_cursor() -> Result<Option<String>, anyhow::Error> {
     // FIXME: Handling of the cursor is missing here. It should be loaded from
     // somewhere (local file, database, cloud storage) and then `SubstreamStream` will
@@ -175,7 +339,98 @@ fn read_block_range(pkg: &Package, module_name: &str) -> Result<(i64, u64), anyh
             .context("argument <stop> is not a valid integer")?,
     };
 
-    return Ok((start, stop));
+    Ok((start, stop))
+}
+
+fn init_logging() -> Result<(), anyhow::Error> {
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info"));
+    let registry = tracing_subscriber::registry()
+        .with(filter)
+        .with(crash_reports::tracing_layer());
+
+    match env::var("SUBSTREAMS_LOG_FORMAT").as_deref() {
+        Err(_) | Ok("pretty") => registry.with(fmt::layer()).init(),
+        Ok("json") => registry.with(fmt::layer().json()).init(),
+        Ok(other) => bail!(
+            "SUBSTREAMS_LOG_FORMAT must be 'pretty' or 'json', got '{}'",
+            other
+        ),
+    }
+
+    Ok(())
+}
+
+fn read_retry_policy() -> Result<RetryPolicy, anyhow::Error> {
+    let mut policy = RetryPolicy::default();
+
+    if let Ok(value) = env::var("SUBSTREAMS_RETRY_MAX_ATTEMPTS") {
+        policy.max_attempts = Some(
+            value
+                .parse::<usize>()
+                .context("SUBSTREAMS_RETRY_MAX_ATTEMPTS is not a valid integer")?,
+        );
+    }
+
+    if let Ok(value) = env::var("SUBSTREAMS_RETRY_BASE_DELAY_MS") {
+        policy.base_delay = Duration::from_millis(
+            value
+                .parse::<u64>()
+                .context("SUBSTREAMS_RETRY_BASE_DELAY_MS is not a valid integer")?,
+        );
+
+        if policy.base_delay.is_zero() {
+            bail!("SUBSTREAMS_RETRY_BASE_DELAY_MS must be greater than 0");
+        }
+    }
+
+    if let Ok(value) = env::var("SUBSTREAMS_RETRY_MAX_DELAY_MS") {
+        policy.max_delay = Duration::from_millis(
+            value
+                .parse::<u64>()
+                .context("SUBSTREAMS_RETRY_MAX_DELAY_MS is not a valid integer")?,
+        );
+
+        if policy.max_delay.is_zero() {
+            bail!("SUBSTREAMS_RETRY_MAX_DELAY_MS must be greater than 0");
+        }
+    }
+
+    if let Ok(value) = env::var("SUBSTREAMS_RETRY_JITTER") {
+        policy.jitter = value
+            .parse::<bool>()
+            .context("SUBSTREAMS_RETRY_JITTER must be 'true' or 'false'")?;
+    }
+
+    Ok(policy)
+}
+
+fn read_exit_after_catchup() -> Result<bool, anyhow::Error> {
+    match env::var("SUBSTREAMS_EXIT_AFTER_CATCHUP") {
+        Ok(value) => value
+            .parse::<bool>()
+            .context("SUBSTREAMS_EXIT_AFTER_CATCHUP must be 'true' or 'false'"),
+        Err(_) => Ok(false),
+    }
+}
+
+fn read_cursor_flush_policy() -> Result<CursorFlushPolicy, anyhow::Error> {
+    let mut every_blocks = 100;
+    if let Ok(value) = env::var("SUBSTREAMS_CURSOR_FLUSH_BLOCKS") {
+        every_blocks = value
+            .parse::<u64>()
+            .context("SUBSTREAMS_CURSOR_FLUSH_BLOCKS is not a valid integer")?;
+    }
+
+    let mut every = Duration::from_secs(10);
+    if let Ok(value) = env::var("SUBSTREAMS_CURSOR_FLUSH_INTERVAL_MS") {
+        every = Duration::from_millis(
+            value
+                .parse::<u64>()
+                .context("SUBSTREAMS_CURSOR_FLUSH_INTERVAL_MS is not a valid integer")?,
+        );
+    }
+
+    Ok(CursorFlushPolicy::new(every_blocks, every))
 }
 
 async fn read_package(input: &str) -> Result<Package, anyhow::Error> {
@@ -195,3 +450,65 @@ async fn read_http_package(input: &str) -> Result<Package, anyhow::Error> {
 
     Package::decode(body).context("decode command")
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cursor(n: u64) -> String {
+        format!("cursor-{}", n)
+    }
+
+    #[test]
+    fn cursor_flush_after_data_block() {
+        let mut policy = CursorFlushPolicy::new(100, Duration::from_secs(3600));
+
+        assert_eq!(policy.on_block(cursor(1), false), None);
+        assert_eq!(policy.on_block(cursor(2), true), Some(cursor(2)));
+        assert_eq!(policy.take_pending(), None);
+    }
+
+    #[test]
+    fn cursor_flush_every_n_empty_blocks() {
+        let mut policy = CursorFlushPolicy::new(3, Duration::from_secs(3600));
+
+        assert_eq!(policy.on_block(cursor(1), false), None);
+        assert_eq!(policy.on_block(cursor(2), false), None);
+        assert_eq!(policy.on_block(cursor(3), false), Some(cursor(3)));
+        assert_eq!(policy.on_block(cursor(4), false), None);
+    }
+
+    #[test]
+    fn cursor_flush_after_interval() {
+        let mut policy = CursorFlushPolicy::new(100, Duration::from_secs(10));
+
+        assert_eq!(policy.on_block(cursor(1), false), None);
+
+        policy.last_flush = Instant::now() - Duration::from_secs(11);
+        assert_eq!(policy.on_block(cursor(2), false), Some(cursor(2)));
+        assert_eq!(policy.on_block(cursor(3), false), None);
+    }
+
+    #[test]
+    fn take_pending_restarts_batch() {
+        let mut policy = CursorFlushPolicy::new(3, Duration::from_secs(3600));
+
+        assert_eq!(policy.on_block(cursor(1), false), None);
+        assert_eq!(policy.on_block(cursor(2), false), None);
+        assert_eq!(policy.take_pending(), Some(cursor(2)));
+        assert_eq!(policy.take_pending(), None);
+
+        // Without the restart, the next block would have been the 3rd of the batch.
+        assert_eq!(policy.on_block(cursor(3), false), None);
+        assert_eq!(policy.on_block(cursor(4), false), None);
+        assert_eq!(policy.on_block(cursor(5), false), Some(cursor(5)));
+    }
+
+    #[test]
+    fn cursor_flush_every_block_when_batching_disabled() {
+        let mut policy = CursorFlushPolicy::new(0, Duration::from_secs(3600));
+
+        assert_eq!(policy.on_block(cursor(1), false), Some(cursor(1)));
+        assert_eq!(policy.on_block(cursor(2), false), Some(cursor(2)));
+    }
+}
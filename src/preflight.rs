@@ -0,0 +1,185 @@
+//! Startup checks run before streaming so that every configuration problem is reported at
+//! once, instead of failing one at a time deep inside the stream loop.
+
+use std::time::Duration;
+
+use anyhow::{anyhow, Error};
+use tokio::{net::TcpStream, time::timeout};
+use tracing::{error, info, warn};
+
+use crate::pb::sf::substreams::v1::{Module, Package};
+use crate::substreams::parse_endpoint_uri;
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+enum Status {
+    Ok(String),
+    Warning(String),
+    Failed(String),
+}
+
+#[derive(Default)]
+struct Report {
+    checks: Vec<(&'static str, Status)>,
+}
+
+impl Report {
+    fn add(&mut self, name: &'static str, status: Status) {
+        self.checks.push((name, status));
+    }
+
+    fn has_failures(&self) -> bool {
+        self.checks
+            .iter()
+            .any(|(_, status)| matches!(status, Status::Failed(_)))
+    }
+
+    fn log(&self) {
+        for (name, status) in &self.checks {
+            match status {
+                Status::Ok(msg) => info!(check = name, "Preflight ok: {}", msg),
+                Status::Warning(msg) => warn!(check = name, "Preflight warning: {}", msg),
+                Status::Failed(msg) => error!(check = name, "Preflight failed: {}", msg),
+            }
+        }
+    }
+}
+
+/// Validates the package, the output module and its type, the endpoint and the API token,
+/// logging the outcome of every check. Returns the decoded package when every check passed.
+pub async fn run(
+    endpoint_url: &str,
+    package_file: &str,
+    module_name: &str,
+    expected_output_type: &str,
+    token: Option<&str>,
+) -> Result<Package, Error> {
+    let mut report = Report::default();
+
+    let package = match crate::read_package(package_file).await {
+        Ok(package) => {
+            report.add("package", Status::Ok(format!("decoded '{}'", package_file)));
+            Some(package)
+        }
+        Err(err) => {
+            report.add("package", Status::Failed(format!("{:#}", err)));
+            None
+        }
+    };
+
+    if let Some(package) = &package {
+        report.add(
+            "module",
+            check_module(package, module_name, expected_output_type),
+        );
+    }
+
+    report.add("endpoint", check_endpoint(endpoint_url).await);
+
+    report.add(
+        "token",
+        match token {
+            Some(_) => Status::Ok("SUBSTREAMS_API_TOKEN is set".to_string()),
+            None => Status::Warning(
+                "SUBSTREAMS_API_TOKEN is not set, hosted endpoints will reject the request"
+                    .to_string(),
+            ),
+        },
+    );
+
+    report.log();
+
+    match package {
+        Some(package) if !report.has_failures() => Ok(package),
+        _ => Err(anyhow!("preflight checks failed, see logged report")),
+    }
+}
+
+fn check_module(package: &Package, module_name: &str, expected_output_type: &str) -> Status {
+    let modules = match package.modules.as_ref() {
+        Some(modules) => &modules.modules,
+        None => return Status::Failed("package does not contain any module".to_string()),
+    };
+
+    let module = match modules.iter().find(|m| m.name == module_name) {
+        Some(module) => module,
+        None => {
+            return Status::Failed(format!(
+                "module '{}' not found in package, available modules are: {}",
+                module_name,
+                modules
+                    .iter()
+                    .map(|m| m.name.as_str())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ))
+        }
+    };
+
+    let declared_type = |module: &Module| {
+        module
+            .output
+            .as_ref()
+            .map(|output| output.r#type.trim_start_matches("proto:").to_string())
+            .unwrap_or_default()
+    };
+
+    let output_type = declared_type(module);
+    if output_type == expected_output_type {
+        return Status::Ok(format!(
+            "module '{}' outputs '{}'",
+            module_name, expected_output_type
+        ));
+    }
+
+    let candidates: Vec<_> = modules
+        .iter()
+        .filter(|m| declared_type(m) == expected_output_type)
+        .map(|m| m.name.as_str())
+        .collect();
+
+    let hint = match candidates.as_slice() {
+        [] => "no module in the package outputs it".to_string(),
+        names => format!("did you mean module {}?", names.join(", ")),
+    };
+
+    Status::Failed(format!(
+        "module '{}' outputs '{}' but the sink decodes '{}', {}",
+        module_name, output_type, expected_output_type, hint
+    ))
+}
+
+async fn check_endpoint(endpoint_url: &str) -> Status {
+    let uri = match parse_endpoint_uri(endpoint_url) {
+        Ok(uri) => uri,
+        Err(err) => return Status::Failed(format!("{:#}", err)),
+    };
+
+    // IPv6 literals keep their brackets in `Uri::host`, which the resolver does not accept.
+    let host = match uri.host() {
+        Some(host) => host
+            .trim_start_matches('[')
+            .trim_end_matches(']')
+            .to_string(),
+        None => return Status::Failed(format!("endpoint '{}' has no host", endpoint_url)),
+    };
+
+    let port = uri.port_u16().unwrap_or(match uri.scheme_str() {
+        Some("https") => 443,
+        _ => 80,
+    });
+
+    // An unreachable endpoint is only a warning, it may be down temporarily and the stream
+    // reconnects following the `RetryPolicy` anyway.
+    match timeout(CONNECT_TIMEOUT, TcpStream::connect((host.as_str(), port))).await {
+        Ok(Ok(_)) => Status::Ok(format!("{}:{} is reachable", host, port)),
+        Ok(Err(err)) => Status::Warning(format!(
+            "unable to connect to {}:{}, will keep retrying: {}",
+            host, port, err
+        )),
+        Err(_) => Status::Warning(format!(
+            "connecting to {}:{} timed out after {:?}, will keep retrying",
+            host, port, CONNECT_TIMEOUT
+        )),
+    }
+}
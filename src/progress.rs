@@ -0,0 +1,219 @@
+//! Tracks streaming throughput to estimate how long until the sink catches up, either with the
+//! requested stop block or, for open ended streams, with the chain head (real time).
+
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::pb::sf::substreams::v1::Clock;
+
+/// Below this lag, an open ended stream is considered live.
+const LIVE_LAG: Duration = Duration::from_secs(60);
+
+struct Sample {
+    at: Instant,
+    block_num: u64,
+    block_timestamp: i64,
+}
+
+/// Throughput observed since the previous report.
+pub struct Progress {
+    pub block: u64,
+    pub blocks_per_sec: f64,
+    pub eta: String,
+}
+
+pub struct SyncProgress {
+    stop_block: u64,
+    report_every: Duration,
+    window_start: Option<Sample>,
+}
+
+impl SyncProgress {
+    /// A `stop_block` of 0 means the stream never ends, as `stop_block_num` in the Substreams
+    /// `Request`.
+    pub fn new(stop_block: u64, report_every: Duration) -> Self {
+        SyncProgress {
+            stop_block,
+            report_every,
+            window_start: None,
+        }
+    }
+
+    /// Records a processed block, returning the progress to report every `report_every`.
+    pub fn on_block(&mut self, clock: &Clock) -> Option<Progress> {
+        let sample = Sample {
+            at: Instant::now(),
+            block_num: clock.number,
+            block_timestamp: block_timestamp(clock),
+        };
+
+        let start = match self.window_start.as_ref() {
+            Some(start) if start.at.elapsed() >= self.report_every => start,
+            Some(_) => return None,
+            None => {
+                self.window_start = Some(sample);
+                return None;
+            }
+        };
+
+        let elapsed = start.at.elapsed().as_secs_f64();
+        let blocks = sample.block_num.saturating_sub(start.block_num) as f64;
+        let blocks_per_sec = blocks / elapsed;
+
+        let eta = if self.stop_block > 0 {
+            let remaining = self.stop_block.saturating_sub(sample.block_num) as f64;
+            if blocks_per_sec > 0.0 {
+                format_eta(remaining / blocks_per_sec)
+            } else {
+                "unknown".to_string()
+            }
+        } else {
+            let lag = lag_seconds(sample.block_timestamp) as f64;
+
+            // How many seconds of chain time we process per second of wall time, we only gain
+            // on the chain head by the amount above 1.
+            let speedup = (sample.block_timestamp - start.block_timestamp) as f64 / elapsed;
+
+            if lag <= LIVE_LAG.as_secs_f64() {
+                "live".to_string()
+            } else if speedup > 1.0 {
+                format_eta(lag / (speedup - 1.0))
+            } else {
+                "not catching up".to_string()
+            }
+        };
+
+        let progress = Progress {
+            block: sample.block_num,
+            blocks_per_sec: (blocks_per_sec * 10.0).round() / 10.0,
+            eta,
+        };
+
+        self.window_start = Some(sample);
+        Some(progress)
+    }
+}
+
+/// Whether the block is close enough to real time to consider the stream caught up.
+pub fn is_live(clock: &Clock) -> bool {
+    lag_seconds(block_timestamp(clock)) <= LIVE_LAG.as_secs() as i64
+}
+
+fn block_timestamp(clock: &Clock) -> i64 {
+    clock.timestamp.as_ref().map(|t| t.seconds).unwrap_or(0)
+}
+
+fn lag_seconds(block_timestamp: i64) -> i64 {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or(0);
+
+    (now - block_timestamp).max(0)
+}
+
+fn format_eta(seconds: f64) -> String {
+    let seconds = seconds.round() as u64;
+    let (days, hours, minutes) = (
+        seconds / 86_400,
+        seconds % 86_400 / 3_600,
+        seconds % 3_600 / 60,
+    );
+
+    match (days, hours, minutes) {
+        (0, 0, 0) => format!("{}s", seconds),
+        (0, 0, m) => format!("{}m", m),
+        (0, h, m) => format!("{}h {}m", h, m),
+        (d, h, _) => format!("{}d {}h", d, h),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn now() -> i64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs() as i64
+    }
+
+    fn clock(number: u64, timestamp: i64) -> Clock {
+        Clock {
+            id: String::new(),
+            number,
+            timestamp: Some(prost_types::Timestamp {
+                seconds: timestamp,
+                nanos: 0,
+            }),
+        }
+    }
+
+    /// Feeds `first`, pretends it was received 10 seconds ago, then feeds `second`.
+    fn progress_over_10s(progress: &mut SyncProgress, first: Clock, second: Clock) -> Progress {
+        assert!(progress.on_block(&first).is_none());
+
+        let start = progress.window_start.as_mut().unwrap();
+        start.at = Instant::now() - Duration::from_secs(10);
+
+        progress.on_block(&second).expect("a progress report")
+    }
+
+    #[test]
+    fn format_eta_unit_boundaries() {
+        assert_eq!(format_eta(59.0), "59s");
+        assert_eq!(format_eta(60.0), "1m");
+        assert_eq!(format_eta(3_599.0), "59m");
+        assert_eq!(format_eta(3_600.0), "1h 0m");
+        assert_eq!(format_eta(86_399.0), "23h 59m");
+        assert_eq!(format_eta(86_400.0), "1d 0h");
+        assert_eq!(format_eta(90_061.0), "1d 1h");
+    }
+
+    #[test]
+    fn on_block_reports_only_every_interval() {
+        let mut progress = SyncProgress::new(1_000, Duration::from_secs(30));
+
+        assert!(progress.on_block(&clock(100, 0)).is_none());
+        assert!(progress.on_block(&clock(101, 0)).is_none());
+    }
+
+    #[test]
+    fn on_block_eta_to_stop_block() {
+        let mut progress = SyncProgress::new(1_000, Duration::from_secs(5));
+
+        let report = progress_over_10s(&mut progress, clock(100, 0), clock(200, 0));
+        assert_eq!(report.block, 200);
+        assert_eq!(report.blocks_per_sec, 10.0);
+        // 800 blocks left at 10 blocks/s
+        assert_eq!(report.eta, "1m");
+    }
+
+    #[test]
+    fn on_block_eta_to_chain_head() {
+        let mut progress = SyncProgress::new(0, Duration::from_secs(5));
+        let start = now() - 3_600;
+
+        // 30s of chain time per 10s of wall time, gaining 2s per second on a ~3570s lag.
+        let report = progress_over_10s(&mut progress, clock(1, start), clock(2, start + 30));
+        assert_eq!(report.eta, "29m");
+    }
+
+    #[test]
+    fn on_block_not_catching_up() {
+        let mut progress = SyncProgress::new(0, Duration::from_secs(5));
+        let start = now() - 3_600;
+
+        let report = progress_over_10s(&mut progress, clock(1, start), clock(2, start + 5));
+        assert_eq!(report.eta, "not catching up");
+    }
+
+    #[test]
+    fn on_block_live() {
+        let mut progress = SyncProgress::new(0, Duration::from_secs(5));
+        let start = now() - 10;
+
+        let report = progress_over_10s(&mut progress, clock(1, start), clock(2, start + 10));
+        assert_eq!(report.eta, "live");
+    }
+}
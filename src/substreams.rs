@@ -1,5 +1,7 @@
 use std::{fmt::Display, sync::Arc, time::Duration};
 
+use anyhow::{anyhow, Context};
+
 use http::{uri::Scheme, Uri};
 use tonic::{
     codegen::http,
@@ -24,17 +26,13 @@ impl Display for SubstreamsEndpoint {
 
 impl SubstreamsEndpoint {
     pub async fn new<S: AsRef<str>>(url: S, token: Option<String>) -> Result<Self, anyhow::Error> {
-        let uri = url
-            .as_ref()
-            .parse::<Uri>()
-            .expect("the url should have been validated by now, so it is a valid Uri");
+        let uri = parse_endpoint_uri(url.as_ref())?;
 
         let endpoint = match uri.scheme().unwrap_or(&Scheme::HTTP).as_str() {
-            "http" => Channel::builder(uri),
             "https" => Channel::builder(uri)
                 .tls_config(ClientTlsConfig::new())
                 .expect("TLS config on this host is invalid"),
-            _ => panic!("invalid uri scheme for firehose endpoint"),
+            _ => Channel::builder(uri),
         }
         .connect_timeout(Duration::from_secs(10))
         .tcp_keepalive(Some(Duration::from_secs(30)));
@@ -49,6 +47,8 @@ impl SubstreamsEndpoint {
         })
     }
 
+    // The interceptor closure must return `tonic::Status` as its error, we don't control its size.
+    #[allow(clippy::result_large_err)]
     pub async fn substreams(
         self: Arc<Self>,
         request: Request,
@@ -76,3 +76,18 @@ impl SubstreamsEndpoint {
         Ok(block_stream)
     }
 }
+
+/// Parses an endpoint in the form `http(s)?://<host>:<port>`, rejecting any other scheme.
+pub fn parse_endpoint_uri(url: &str) -> Result<Uri, anyhow::Error> {
+    let uri = url
+        .parse::<Uri>()
+        .with_context(|| format!("invalid endpoint url '{}'", url))?;
+
+    match uri.scheme().unwrap_or(&Scheme::HTTP).as_str() {
+        "http" | "https" => Ok(uri),
+        scheme => Err(anyhow!(
+            "invalid uri scheme '{}' for substreams endpoint, expected http or https",
+            scheme
+        )),
+    }
+}
@@ -1,4 +1,4 @@
-use anyhow::{anyhow, Error};
+use anyhow::Error;
 use async_stream::try_stream;
 use futures03::{Stream, StreamExt};
 use std::{
@@ -8,7 +8,8 @@ use std::{
     time::Duration,
 };
 use tokio::time::sleep;
-use tokio_retry::strategy::ExponentialBackoff;
+use tokio_retry::strategy::jitter;
+use tracing::{debug, info, warn};
 
 use crate::pb::sf::substreams::rpc::v2::{
     response::Message, BlockScopedData, BlockUndoSignal, Request, Response,
@@ -17,6 +18,11 @@ use crate::pb::sf::substreams::v1::Modules;
 
 use crate::substreams::SubstreamsEndpoint;
 
+/// Returned by the stream when the `RetryPolicy` gave up reconnecting.
+#[derive(Debug, thiserror::Error)]
+#[error("backoff requested to stop retrying, quitting")]
+pub struct RetriesExhausted;
+
 pub enum BlockResponse {
     New(BlockScopedData),
     Undo(BlockUndoSignal),
@@ -26,6 +32,46 @@ pub struct SubstreamsStream {
     stream: Pin<Box<dyn Stream<Item = Result<BlockResponse, Error>> + Send>>,
 }
 
+/// Controls how long we wait between reconnection attempts and when we give up.
+#[derive(Clone, Debug)]
+pub struct RetryPolicy {
+    /// Maximum number of consecutive retries before the stream fails, `None` retries forever.
+    pub max_attempts: Option<usize>,
+    /// Delay before the first retry, doubled on each following retry up to `max_delay`.
+    pub base_delay: Duration,
+    pub max_delay: Duration,
+    /// Randomizes each delay so that many sinks restarting together do not reconnect in lockstep.
+    pub jitter: bool,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_attempts: None,
+            base_delay: Duration::from_millis(500),
+            max_delay: Duration::from_secs(45),
+            jitter: false,
+        }
+    }
+}
+
+impl RetryPolicy {
+    fn backoff(&self) -> Box<dyn Iterator<Item = Duration> + Send> {
+        // tokio-retry's `ExponentialBackoff` raises its base to the power of the attempt, which
+        // is not a usable "first delay", so the doubling is computed here.
+        let (max_delay, with_jitter) = (self.max_delay, self.jitter);
+        let delays = std::iter::successors(Some(self.base_delay.min(max_delay)), move |delay| {
+            Some(delay.saturating_mul(2).min(max_delay))
+        })
+        .map(move |delay| if with_jitter { jitter(delay) } else { delay });
+
+        match self.max_attempts {
+            Some(max_attempts) => Box::new(delays.take(max_attempts)),
+            None => Box::new(delays),
+        }
+    }
+}
+
 impl SubstreamsStream {
     pub fn new(
         endpoint: Arc<SubstreamsEndpoint>,
@@ -34,6 +80,7 @@ impl SubstreamsStream {
         output_module_name: String,
         start_block: i64,
         end_block: u64,
+        retry_policy: RetryPolicy,
     ) -> Self {
         SubstreamsStream {
             stream: Box::pin(stream_blocks(
@@ -43,6 +90,7 @@ impl SubstreamsStream {
                 output_module_name,
                 start_block,
                 end_block,
+                retry_policy,
             )),
         }
     }
@@ -56,17 +104,19 @@ fn stream_blocks(
     output_module_name: String,
     start_block_num: i64,
     stop_block_num: u64,
+    retry_policy: RetryPolicy,
 ) -> impl Stream<Item = Result<BlockResponse, Error>> {
-    let mut latest_cursor = cursor.unwrap_or_else(|| "".to_string());
-    let mut backoff = ExponentialBackoff::from_millis(500).max_delay(Duration::from_secs(45));
+    let mut latest_cursor = cursor.unwrap_or_default();
+    let mut backoff = retry_policy.backoff();
 
     try_stream! {
         loop {
-            println!("Blockstreams disconnected, connecting (endpoint {}, start block {}, stop block {}, cursor {})",
-                &endpoint,
-                start_block_num,
-                stop_block_num,
-                &latest_cursor
+            info!(
+                endpoint = %endpoint,
+                start_block = start_block_num,
+                stop_block = stop_block_num,
+                cursor = %latest_cursor,
+                "Blockstreams disconnected, connecting"
             );
 
             let result = endpoint.clone().substreams(Request {
@@ -86,14 +136,14 @@ fn stream_blocks(
 
             match result {
                 Ok(stream) => {
-                    println!("Blockstreams connected");
+                    info!("Blockstreams connected");
 
                     let mut encountered_error = false;
                     for await response in stream{
                         match process_substreams_response(response).await {
                             BlockProcessedResult::BlockScopedData(block_scoped_data) => {
                                 // Reset backoff because we got a good value from the stream
-                                backoff = ExponentialBackoff::from_millis(500).max_delay(Duration::from_secs(45));
+                                backoff = retry_policy.backoff();
 
                                 let cursor = block_scoped_data.cursor.clone();
                                 yield BlockResponse::New(block_scoped_data);
@@ -102,7 +152,7 @@ fn stream_blocks(
                             },
                             BlockProcessedResult::BlockUndoSignal(block_undo_signal) => {
                                 // Reset backoff because we got a good value from the stream
-                                backoff = ExponentialBackoff::from_millis(500).max_delay(Duration::from_secs(45));
+                                backoff = retry_policy.backoff();
 
                                 let cursor = block_undo_signal.last_valid_cursor.clone();
                                 yield BlockResponse::Undo(block_undo_signal);
@@ -117,7 +167,7 @@ fn stream_blocks(
                                     return Err(anyhow::Error::new(status.clone()))?;
                                 }
 
-                                println!("Received tonic error {:#}", status);
+                                warn!("Received tonic error {:#}", status);
                                 encountered_error = true;
                                 break;
                             },
@@ -125,7 +175,7 @@ fn stream_blocks(
                     }
 
                     if !encountered_error {
-                        println!("Stream completed, reached end block");
+                        info!("Stream completed, reached end block");
                         return
                     }
                 },
@@ -134,7 +184,7 @@ fn stream_blocks(
                     // case where we actually _want_ to back off in case we keep
                     // having connection errors.
 
-                    println!("Unable to connect to endpoint: {:#}", e);
+                    warn!("Unable to connect to endpoint: {:#}", e);
                 }
             }
 
@@ -142,7 +192,7 @@ fn stream_blocks(
             if let Some(duration) = backoff.next() {
                 sleep(duration).await
             } else {
-                return Err(anyhow!("backoff requested to stop retrying, quitting"))?;
+                return Err(RetriesExhausted)?;
             }
         }
     }
@@ -203,12 +253,12 @@ async fn process_substreams_response(
             //     })
             //     .collect();
 
-            // println!("Progess {}", progresses.join(", "));
+            // debug!("Progess {}", progresses.join(", "));
 
             BlockProcessedResult::Skip()
         }
         None => {
-            println!("Got None on substream message");
+            debug!("Got None on substream message");
             BlockProcessedResult::Skip()
         }
         _ => BlockProcessedResult::Skip(),
@@ -222,3 +272,47 @@ impl Stream for SubstreamsStream {
         self.stream.poll_next_unpin(cx)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn delays(policy: &RetryPolicy, count: usize) -> Vec<u64> {
+        policy
+            .backoff()
+            .take(count)
+            .map(|delay| delay.as_millis() as u64)
+            .collect()
+    }
+
+    #[test]
+    fn backoff_doubles_from_base_delay() {
+        let policy = RetryPolicy::default();
+
+        assert_eq!(
+            delays(&policy, 9),
+            vec![500, 1000, 2000, 4000, 8000, 16000, 32000, 45000, 45000]
+        );
+    }
+
+    #[test]
+    fn backoff_respects_custom_delays() {
+        let policy = RetryPolicy {
+            base_delay: Duration::from_millis(100),
+            max_delay: Duration::from_millis(250),
+            ..Default::default()
+        };
+
+        assert_eq!(delays(&policy, 4), vec![100, 200, 250, 250]);
+    }
+
+    #[test]
+    fn backoff_stops_after_max_attempts() {
+        let policy = RetryPolicy {
+            max_attempts: Some(3),
+            ..Default::default()
+        };
+
+        assert_eq!(delays(&policy, 10), vec![500, 1000, 2000]);
+    }
+}